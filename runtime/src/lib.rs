@@ -0,0 +1,156 @@
+#[macro_use]
+extern crate log;
+extern crate bytes;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_timer;
+extern crate tokio_util;
+
+// Test-only: drives `AsDatumCodec` over a real `Framed` transport in
+// `codec::tests`. Kept off the futures 0.1 `futures` crate this module
+// otherwise depends on, so the two generations never collide.
+#[cfg(test)]
+extern crate futures_util;
+#[cfg(test)]
+extern crate tokio;
+
+mod codec;
+mod source;
+
+pub use codec::AsDatumCodec;
+pub use source::{GrowthMode, SourceCtrl, TimerSource};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Degradation level of an adaptive source, e.g. a rung on a video encoding
+/// ladder. Lower is more degraded.
+pub type Level = usize;
+
+/// The body of an `AsDatum`: either the real payload bytes, or just the
+/// declared size of a probe. Keeping probes as a bare size means a probe
+/// datum decoded off the wire never has to allocate the zero-filled payload
+/// it stands in for.
+#[derive(Debug)]
+pub(crate) enum Payload {
+    Data(Vec<u8>),
+    Probe(usize),
+}
+
+impl Payload {
+    fn len(&self) -> usize {
+        match *self {
+            Payload::Data(ref bytes) => bytes.len(),
+            Payload::Probe(size) => size,
+        }
+    }
+}
+
+/// A unit of data flowing from a `TimerSource` to the sink: either real
+/// payload tagged with the level it was produced at, or a probe standing in
+/// for `size` bytes of spare-bandwidth traffic.
+///
+/// Probe datums additionally carry the micros-since-epoch timestamp at which
+/// they were sent, so the receiver can echo it back for round-trip and clock
+/// offset estimation.
+#[derive(Debug)]
+pub struct AsDatum {
+    level: Level,
+    sent_at: Option<u64>,
+    payload: Payload,
+}
+
+impl AsDatum {
+    pub fn new(level: Level, payload: Vec<u8>) -> AsDatum {
+        AsDatum {
+            level: level,
+            sent_at: None,
+            payload: Payload::Data(payload),
+        }
+    }
+
+    pub fn probe(size: usize) -> AsDatum {
+        AsDatum {
+            level: 0,
+            sent_at: Some(now_micros()),
+            payload: Payload::Probe(size),
+        }
+    }
+
+    /// Reconstructs a datum decoded off the wire, preserving the sender's
+    /// original `sent_at` rather than re-stamping it on arrival.
+    pub(crate) fn from_wire(level: Level, sent_at: Option<u64>, payload: Payload) -> AsDatum {
+        AsDatum {
+            level: level,
+            sent_at: sent_at,
+            payload: payload,
+        }
+    }
+
+    pub(crate) fn payload(&self) -> &Payload {
+        &self.payload
+    }
+
+    pub fn len(&self) -> usize {
+        self.payload.len()
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn is_probe(&self) -> bool {
+        match self.payload {
+            Payload::Probe(_) => true,
+            Payload::Data(_) => false,
+        }
+    }
+
+    /// Micros-since-epoch at which this datum was sent, i.e. `t1`. Only set
+    /// for probe datums.
+    pub fn sent_at(&self) -> Option<u64> {
+        self.sent_at
+    }
+}
+
+/// Current time as micros since the Unix epoch, used to stamp probe datums
+/// and to timestamp their acknowledgements.
+pub fn now_micros() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).expect(
+        "system clock is before the Unix epoch",
+    );
+    now.as_secs() * 1_000_000 + now.subsec_nanos() as u64 / 1_000
+}
+
+/// Implemented by a data source that can be driven by a `TimerSource`: it
+/// hands over the next datum's size on each tick and reacts to adaptation
+/// signals from the control loop.
+pub trait Adapt {
+    /// Size in bytes of the next datum to send, or `0` to skip this tick.
+    fn next_datum(&mut self) -> usize;
+
+    /// The degradation level the most recent `next_datum` was produced at.
+    fn current_level(&self) -> Level;
+
+    /// How often, in milliseconds, `next_datum` should be polled.
+    fn period_in_ms(&self) -> u64;
+
+    /// Adapt to a new target send rate, in kbps.
+    fn adapt(&mut self, rate: f64);
+
+    /// Step down one degradation level.
+    fn dec_degradation(&mut self);
+}
+
+/// Marker trait for sources that may be driven under experiment harnesses
+/// (e.g. trace replay) rather than live capture.
+pub trait Experiment {}
+
+/// Signals sent from the control loop to a running `TimerSource`.
+#[derive(Debug, Clone, Copy)]
+pub enum AdaptSignal {
+    ToRate(f64),
+    DecreaseDegradation,
+    StartProbe(f64),
+    IncreaseProbePace,
+    StopProbe,
+}
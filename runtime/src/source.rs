@@ -1,20 +1,66 @@
 use super::{Adapt, AdaptSignal, Experiment};
-use super::AsDatum;
-use futures::Stream;
+use super::{now_micros, AsDatum};
+use futures::{Future, Stream};
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded};
+use futures::sync::oneshot;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, AtomicBool, Ordering};
 use std::time::Duration;
 use tokio_core::reactor::Handle;
 use tokio_timer;
 
 type AdaptControl = UnboundedSender<AdaptSignal>;
 type DataChannel = UnboundedReceiver<AsDatum>;
+type ProbeAckControl = UnboundedSender<ProbeAck>;
 
-pub type SourceCtrl = (AdaptControl, DataChannel, Arc<AtomicUsize>, Arc<AtomicBool>);
+/// Sending `()` (or simply dropping this handle) tells the spawned
+/// `TimerSource` task to stop. Unlike every other handle in `SourceCtrl`,
+/// this one is not meant to be discarded with `_`: the source keeps running
+/// only as long as it is held, and dropping it early tears the task down
+/// with no warning. Both sending and dropping are treated identically and
+/// intentionally (see `Incoming::Shutdown` in `spawn`) rather than dropping
+/// being an accidental side effect of `oneshot::Canceled`.
+type ShutdownHandle = oneshot::Sender<()>;
+
+pub type SourceCtrl = (
+    AdaptControl,
+    DataChannel,
+    Arc<AtomicUsize>,
+    Arc<AtomicBool>,
+    ProbeAckControl,
+    Arc<AtomicU64>,
+    // Keep alive for as long as the source should keep running; see
+    // `ShutdownHandle`.
+    ShutdownHandle,
+);
 
 pub struct TimerSource;
 
+/// Computes round-trip time and clock offset from the four timestamps of an
+/// NTP-style exchange: `t1` probe sent, `t2` probe received, `t3` reply
+/// sent, `t4` reply received. Both are in micros, and `rtt` may come out
+/// negative under clock skew or a malformed ack; callers clamp as needed.
+fn ntp_rtt_offset(t1: u64, t2: u64, t3: u64, t4: u64) -> (i64, i64) {
+    let (t1, t2, t3, t4) = (t1 as i64, t2 as i64, t3 as i64, t4 as i64);
+    let rtt = (t4 - t1) - (t3 - t2);
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    (rtt, offset)
+}
+
+/// The receiver's side of a single probe datum's round trip, mirroring an
+/// NTP-style time-sync exchange: `t1` is echoed back unchanged from the
+/// probe's `AsDatum::sent_at()` so the sender can match this ack to the
+/// probe it belongs to (multiple probes can be in flight at once during
+/// ramp-up, so acks are not assumed to arrive in send order); `t2` is when
+/// the probe arrived, `t3` is when the reply was sent back. Fed back to the
+/// source through the `ProbeAckControl` half of `SourceCtrl`.
+pub struct ProbeAck {
+    pub t1: u64,
+    pub t2: u64,
+    pub t3: u64,
+}
+
 /// `ProbeTracker` controls the probing behavior. The core function is `next`
 /// that returns an `Option<AsDatum>`, it is either a probe datum, or indicates
 /// the probing has done.
@@ -39,18 +85,59 @@ struct ProbeTracker {
 
     /// Step in each `inc_pace`.
     pub delta: usize,
+
+    /// Which growth function `inc_pace` uses to approach `target_pace`.
+    pub growth_mode: GrowthMode,
+
+    /// The pace at which the previous probing episode was declared
+    /// unsustainable (the last level before back-off). This is the `W_max`
+    /// term in TCP CUBIC's window function.
+    pub w_max: f64,
+
+    /// Number of `IncreaseProbePace` ticks processed since `start_probe`,
+    /// used to derive elapsed time `t` for the cubic function.
+    pub ticks_since_probe_start: u64,
+
+    /// Ticks elapsed since the last `IncreaseProbePace` while still ramping
+    /// up (`pace < target_pace`). Reset whenever pace advances; see `tick`.
+    pub idle_ticks: u64,
+
+    /// How many idle ticks may elapse before `tick` auto-aborts a stalled
+    /// probing episode.
+    pub idle_timeout: u64,
 }
 
 const NUM_PROBE_REQUIRED: usize = 5;
 
+/// CUBIC window-growth constants, as in RFC 8312: `C` scales the cubic term
+/// and `beta` is the multiplicative back-off factor applied to `w_max` when
+/// computing the inflection point `K`.
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.3;
+
+/// Selects how `ProbeTracker::inc_pace` grows `pace` toward `target_pace` on
+/// each `IncreaseProbePace` tick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrowthMode {
+    /// Pure additive increase by `delta` each tick, as in TCP AIMD.
+    Aimd,
+    /// TCP CUBIC's window function, concave below `w_max` and convex past it.
+    Cubic,
+}
+
 impl ProbeTracker {
-    fn new(tick_period: u64) -> ProbeTracker {
+    fn new(tick_period: u64, growth_mode: GrowthMode, idle_timeout: u64) -> ProbeTracker {
         ProbeTracker {
             tick_period: tick_period,
             target_in_kbps: 0.0,
             target_pace: 0,
             delta: 0,
             pace: 0,
+            growth_mode: growth_mode,
+            w_max: 0.0,
+            ticks_since_probe_start: 0,
+            idle_ticks: 0,
+            idle_timeout: idle_timeout,
         }
     }
 
@@ -64,19 +151,78 @@ impl ProbeTracker {
 
         self.delta = self.target_pace / NUM_PROBE_REQUIRED;
         self.pace = self.delta;
+        self.idle_ticks = 0;
+        // Elapsed-tick count is per-episode, not cumulative: without this
+        // reset, an episode that starts right after a prior one fully ramped
+        // (no intervening `stop_probe`) would inherit a stale, already-large
+        // `ticks_since_probe_start` and jump straight past the cubic ramp.
+        self.ticks_since_probe_start = 0;
+        // `w_max` defaults to 0 until a probe has ever backed off, which
+        // otherwise sends `K` (the cubic inflection point) to zero and keeps
+        // `cubic_pace` pinned at the `delta` floor for a very long time — far
+        // slower than the plain-AIMD mode CUBIC is meant to improve on.
+        // Seed it with this episode's own target so the very first probe
+        // ramps at a realistic pace instead of assuming a prior back-off at 0.
+        if self.w_max <= 0.0 {
+            self.w_max = self.target_pace as f64;
+        }
     }
 
-    /// Probing is the additive increase phase (as AIMD in TCP).
+    /// Advances `pace` toward `target_pace` for one tick, following
+    /// `growth_mode`. Returns `true` while still increasing, `false` once
+    /// `pace` has reached `target_pace`.
     pub fn inc_pace(&mut self) -> bool {
-        if self.pace < self.target_pace {
-            self.pace = self.pace + self.delta;
-            true
-        } else {
-            false
+        self.idle_ticks = 0;
+        if self.pace >= self.target_pace {
+            return false;
+        }
+
+        self.ticks_since_probe_start += 1;
+        match self.growth_mode {
+            GrowthMode::Aimd => {
+                self.pace = self.pace + self.delta;
+            }
+            GrowthMode::Cubic => {
+                self.pace = self.cubic_pace() as usize;
+            }
+        }
+        true
+    }
+
+    /// Call once per timer tick while a probe may be outstanding. Counts
+    /// ticks where pace is still ramping up without a fresh
+    /// `IncreaseProbePace`; once that idle streak exceeds `idle_timeout`,
+    /// aborts the probe via `stop_probe` and returns `true`. A probe that
+    /// has already ramped to `target_pace` and is merely awaiting
+    /// `StopProbe` is not considered idle.
+    pub fn tick(&mut self) -> bool {
+        if self.target_pace == 0 || self.pace >= self.target_pace {
+            self.idle_ticks = 0;
+            return false;
+        }
+
+        self.idle_ticks += 1;
+        if self.idle_ticks > self.idle_timeout {
+            self.stop_probe();
+            return true;
         }
+        false
+    }
+
+    /// `w_cubic(t) = C*(t - K)^3 + w_max`, clamped to `[delta, target_pace]`.
+    /// `t` is seconds elapsed since `start_probe`, derived from the tick
+    /// counter and `tick_period`; `K` is the time to reach `w_max` again.
+    fn cubic_pace(&self) -> f64 {
+        let t = self.ticks_since_probe_start as f64 * self.tick_period as f64 / 1000.0;
+        let k = (self.w_max * CUBIC_BETA / CUBIC_C).cbrt();
+        let w_cubic = CUBIC_C * (t - k).powi(3) + self.w_max;
+        w_cubic.max(self.delta as f64).min(self.target_pace as f64)
     }
 
     pub fn stop_probe(&mut self) {
+        self.w_max = self.pace as f64;
+        self.ticks_since_probe_start = 0;
+        self.idle_ticks = 0;
         self.target_in_kbps = 0.0;
         self.target_pace = 0;
         self.pace = 0;
@@ -95,10 +241,21 @@ impl ProbeTracker {
 enum Incoming {
     Timer,
     Adapt(AdaptSignal),
+    ProbeAck(ProbeAck),
+    Shutdown,
 }
 
 impl TimerSource {
-    pub fn spawn<As: Adapt + Experiment + 'static>(mut source: As, handle: Handle) -> SourceCtrl {
+    /// `probe_idle_timeout` bounds how many ticks may pass without an
+    /// `IncreaseProbePace` signal while a probe is still ramping up before
+    /// the episode is auto-aborted, in case the controller stalls or the
+    /// link goes idle mid-probe. See `ProbeTracker::tick`.
+    pub fn spawn<As: Adapt + Experiment + 'static>(
+        mut source: As,
+        handle: Handle,
+        growth_mode: GrowthMode,
+        probe_idle_timeout: u64,
+    ) -> SourceCtrl {
         let timer_tick = source.period_in_ms();
         let timer = tokio_timer::wheel()
             .tick_duration(Duration::from_millis(1))
@@ -114,19 +271,44 @@ impl TimerSource {
         let counter = Arc::new(AtomicUsize::new(0));
         let counter_clone = counter.clone();
 
-        let mut prober = ProbeTracker::new(timer_tick);
+        let mut prober = ProbeTracker::new(timer_tick, growth_mode, probe_idle_timeout);
         let probe_done = Arc::new(AtomicBool::new(false));
         let probe_done_clone = probe_done.clone();
 
-        let work = timer.select(adapter).for_each(
-            move |incoming| match incoming {
+        let (probe_ack_tx, probe_ack_rx) = unbounded();
+        let probe_ack_stream = probe_ack_rx.map(Incoming::ProbeAck);
+        let rtt_micros = Arc::new(AtomicU64::new(0));
+        let rtt_micros_clone = rtt_micros.clone();
+        let mut outstanding_probes: VecDeque<u64> = VecDeque::new();
+
+        // Sending `()` and dropping `shutdown_tx` both resolve `shutdown_rx`
+        // (the latter with `Canceled`); both are treated as the same
+        // intentional `Incoming::Shutdown` signal rather than letting
+        // `Canceled` fall out as a distinct, undocumented stream error.
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let shutdown = shutdown_rx
+            .then(|_result| Ok(Incoming::Shutdown))
+            .into_stream();
+
+        let work = timer
+            .select(adapter)
+            .select(probe_ack_stream)
+            .select(shutdown)
+            .for_each(move |incoming| match incoming {
                 Incoming::Timer => {
+                    if prober.tick() {
+                        probe_done_clone.clone().store(true, Ordering::SeqCst);
+                    }
+
                     let size = source.next_datum();
                     if size == 0 {
                         return Ok(());
                     }
 
                     if let Some(p) = prober.next() {
+                        if let Some(t1) = p.sent_at() {
+                            outstanding_probes.push_back(t1);
+                        }
                         counter_clone.clone().fetch_add(p.len(), Ordering::SeqCst);
                         data_tx
                             .unbounded_send(p)
@@ -168,10 +350,235 @@ impl TimerSource {
                     prober.stop_probe();
                     Ok(())
                 }
-            },
-        );
+                Incoming::ProbeAck(ack) => {
+                    let t4 = now_micros();
+                    if let Some(pos) = outstanding_probes.iter().position(|&t1| t1 == ack.t1) {
+                        outstanding_probes.remove(pos);
+                        let (rtt, offset) = ntp_rtt_offset(ack.t1, ack.t2, ack.t3, t4);
+                        debug!("probe rtt={}us offset={}us", rtt, offset);
+                        rtt_micros_clone.clone().store(
+                            rtt.max(0) as u64,
+                            Ordering::SeqCst,
+                        );
+                    }
+                    Ok(())
+                }
+                Incoming::Shutdown => Err(()),
+            });
         handle.spawn(work);
 
-        (adapt_tx, data_rx, counter.clone(), probe_done)
+        (
+            adapt_tx,
+            data_rx,
+            counter.clone(),
+            probe_done,
+            probe_ack_tx,
+            rtt_micros,
+            shutdown_tx,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    struct TestSource;
+
+    impl Adapt for TestSource {
+        fn next_datum(&mut self) -> usize {
+            10
+        }
+        fn current_level(&self) -> Level {
+            0
+        }
+        fn period_in_ms(&self) -> u64 {
+            5
+        }
+        fn adapt(&mut self, _rate: f64) {}
+        fn dec_degradation(&mut self) {}
+    }
+
+    impl Experiment for TestSource {}
+
+    #[test]
+    fn sending_on_the_shutdown_handle_stops_the_task_and_closes_the_data_channel() {
+        let mut core = Core::new().expect("reactor core");
+        let handle = core.handle();
+        let (_, data_rx, _, _, _, _, shutdown_tx) =
+            TimerSource::spawn(TestSource, handle, GrowthMode::Aimd, 1000);
+
+        shutdown_tx.send(()).expect("send shutdown");
+
+        let result = core.run(data_rx.collect());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn dropping_the_shutdown_handle_also_stops_the_task() {
+        let mut core = Core::new().expect("reactor core");
+        let handle = core.handle();
+        let (_, data_rx, _, _, _, _, shutdown_tx) =
+            TimerSource::spawn(TestSource, handle, GrowthMode::Aimd, 1000);
+
+        drop(shutdown_tx);
+
+        let result = core.run(data_rx.collect());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn ntp_rtt_offset_recovers_known_values() {
+        // Sender and receiver clocks offset by 1000us, with 200us of
+        // one-way latency in each direction and 50us spent on the receiver
+        // between arrival and reply.
+        let t1 = 1_000_000u64;
+        let t2 = t1 + 1_000 + 200;
+        let t3 = t2 + 50;
+        let t4 = t3 + 200 - 1_000;
+
+        let (rtt, offset) = ntp_rtt_offset(t1, t2, t3, t4);
+        assert_eq!(rtt, 400);
+        assert_eq!(offset, 1_000);
+    }
+
+    #[test]
+    fn ntp_rtt_offset_is_zero_for_a_zero_latency_loopback() {
+        let (rtt, offset) = ntp_rtt_offset(10, 10, 10, 10);
+        assert_eq!(rtt, 0);
+        assert_eq!(offset, 0);
+    }
+
+    #[test]
+    fn first_ever_probe_episode_does_not_stall_at_the_delta_floor() {
+        // A fresh `ProbeTracker` has no prior back-off to seed `w_max`
+        // from. Left at 0.0, `K` (the cubic inflection point) collapses to
+        // zero and `cubic_pace` sits at the `delta` floor for a very long
+        // time (nearly 800 ticks with these values) instead of ramping.
+        // Seeding a non-zero `w_max` in `start_probe` keeps the very first
+        // tick well above the floor.
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Cubic, 1000);
+        tracker.start_probe(800.0);
+
+        assert!(tracker.inc_pace());
+        assert!(tracker.pace > tracker.delta * 2);
+    }
+
+    #[test]
+    fn start_probe_resets_ticks_since_probe_start_for_a_fresh_episode() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Cubic, 1000);
+        tracker.start_probe(800.0);
+        while tracker.inc_pace() {}
+        assert_eq!(tracker.pace, tracker.target_pace);
+        assert!(tracker.ticks_since_probe_start > 0);
+
+        // A new episode starts right after the last one fully ramped, with
+        // no intervening `stop_probe`. It must ramp from the bottom again,
+        // not inherit the stale tick count and jump straight to the target.
+        tracker.start_probe(1600.0);
+        assert_eq!(tracker.ticks_since_probe_start, 0);
+        assert_eq!(tracker.pace, tracker.delta);
+        assert!(tracker.pace < tracker.target_pace);
+    }
+
+    #[test]
+    fn tick_aborts_the_probe_after_idle_timeout_ticks() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Aimd, 3);
+        tracker.start_probe(800.0);
+
+        // No fresh `IncreaseProbePace` arrives; idle ticks accumulate.
+        assert!(!tracker.tick());
+        assert!(!tracker.tick());
+        assert!(!tracker.tick());
+        assert!(tracker.tick());
+
+        // `stop_probe` ran: the episode is fully reset.
+        assert_eq!(tracker.target_pace, 0);
+        assert_eq!(tracker.pace, 0);
+    }
+
+    #[test]
+    fn tick_does_not_fire_once_pace_has_reached_target() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Aimd, 2);
+        tracker.start_probe(800.0);
+        while tracker.inc_pace() {}
+        assert_eq!(tracker.pace, tracker.target_pace);
+
+        // Fully ramped and merely awaiting `StopProbe`: ticking indefinitely
+        // must not be mistaken for a stall.
+        for _ in 0..10 {
+            assert!(!tracker.tick());
+        }
+        assert_eq!(tracker.target_pace, tracker.pace);
+    }
+
+    #[test]
+    fn inc_pace_resets_the_idle_counter() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Aimd, 2);
+        tracker.start_probe(800.0);
+
+        assert!(!tracker.tick());
+        assert!(!tracker.tick());
+        assert!(tracker.inc_pace());
+        // The idle streak was reset by `inc_pace`, so it has to exceed
+        // `idle_timeout` again before the probe aborts, not just one more tick.
+        assert!(!tracker.tick());
+        assert!(!tracker.tick());
+        assert!(tracker.tick());
+    }
+
+    #[test]
+    fn cubic_growth_stays_in_bounds_and_reaches_target() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Cubic, 1000);
+        tracker.start_probe(800.0);
+        tracker.w_max = tracker.target_pace as f64 * 0.5;
+
+        while tracker.inc_pace() {
+            assert!(tracker.pace >= tracker.delta);
+            assert!(tracker.pace <= tracker.target_pace);
+        }
+        assert_eq!(tracker.pace, tracker.target_pace);
+    }
+
+    #[test]
+    fn cubic_pace_returns_to_w_max_at_the_inflection_point_k() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Cubic, 1000);
+        tracker.target_pace = 100_000;
+        tracker.delta = 100;
+        tracker.w_max = 50_000.0;
+
+        let k = (tracker.w_max * CUBIC_BETA / CUBIC_C).cbrt();
+        tracker.ticks_since_probe_start = (k * 1000.0 / tracker.tick_period as f64).round() as u64;
+
+        assert!((tracker.cubic_pace() - tracker.w_max).abs() < 1000.0);
+    }
+
+    #[test]
+    fn cubic_growth_is_concave_then_convex_around_w_max() {
+        let mut tracker = ProbeTracker::new(10, GrowthMode::Cubic, 1000);
+        tracker.target_pace = 1_000_000;
+        tracker.delta = 1;
+        tracker.w_max = 200_000.0;
+
+        let k_ticks = {
+            let k = (tracker.w_max * CUBIC_BETA / CUBIC_C).cbrt();
+            (k * 1000.0 / tracker.tick_period as f64).round() as u64
+        };
+
+        // Well before K the step per tick should be small (concave,
+        // flattening as pace approaches w_max); well past K it should be
+        // large (convex, growing away from w_max).
+        tracker.ticks_since_probe_start = k_ticks / 4;
+        let early_pace = tracker.cubic_pace();
+        tracker.ticks_since_probe_start += 1;
+        let early_step = tracker.cubic_pace() - early_pace;
+
+        tracker.ticks_since_probe_start = k_ticks * 2;
+        let late_pace = tracker.cubic_pace();
+        tracker.ticks_since_probe_start += 1;
+        let late_step = tracker.cubic_pace() - late_pace;
+
+        assert!(late_step > early_step);
     }
 }